@@ -0,0 +1,105 @@
+//! Per-token network configuration, so a single gateway process can serve
+//! several independent virtual subnets ("tenants").
+//!
+//! `NetworkConfigProvider` is the seam between the transport code in
+//! `service::udp_service` and wherever tokens are actually issued (an
+//! in-memory table today, a database or control-plane service tomorrow).
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Resolved network parameters for one token.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    pub virtual_gateway: u32,
+    pub virtual_netmask: u32,
+    /// Inclusive lower/upper bound of the address pool, excluding the gateway.
+    pub address_range: (u32, u32),
+    pub max_devices: usize,
+    /// Whether clients registering under this token must present the ECDH
+    /// handshake parameters. Lets the handshake/AEAD feature be rolled out
+    /// one token at a time: `false` still accepts old clients over a
+    /// plaintext session, `true` rejects anyone that didn't handshake.
+    pub require_handshake: bool,
+}
+
+impl NetworkConfig {
+    /// Number of devices this network can hold: the size of the configured
+    /// address range, capped at `max_devices`.
+    pub fn capacity(&self) -> usize {
+        (self.address_range.1.saturating_sub(self.address_range.0) as usize + 1)
+            .min(self.max_devices)
+    }
+}
+
+/// Resolves a registration token to the `NetworkConfig` it is allowed to
+/// join. Returning `None` means the token is unknown/unauthorized and the
+/// registration must be rejected.
+pub trait NetworkConfigProvider: Send + Sync {
+    fn resolve(&self, token: &str) -> Option<NetworkConfig>;
+}
+
+/// Default provider: an in-memory table filled through `insert`, with an
+/// optional fallback config from `with_default`. It does not read a config
+/// file. Good enough for single-tenant deployments and as a drop-in before
+/// a SQL-backed provider is wired up.
+pub struct StaticNetworkConfigProvider {
+    networks: RwLock<HashMap<String, NetworkConfig>>,
+    default: Option<NetworkConfig>,
+}
+
+impl StaticNetworkConfigProvider {
+    pub fn new() -> Self {
+        Self { networks: RwLock::new(HashMap::new()), default: None }
+    }
+
+    /// Build a provider that hands every token the same subnet, preserving
+    /// the previous single-tenant behaviour.
+    pub fn with_default(config: NetworkConfig) -> Self {
+        Self { networks: RwLock::new(HashMap::new()), default: Some(config) }
+    }
+
+    pub fn insert(&self, token: String, config: NetworkConfig) {
+        self.networks.write().unwrap().insert(token, config);
+    }
+}
+
+impl NetworkConfigProvider for StaticNetworkConfigProvider {
+    fn resolve(&self, token: &str) -> Option<NetworkConfig> {
+        if let Some(config) = self.networks.read().unwrap().get(token) {
+            return Some(config.clone());
+        }
+        self.default.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_config() -> NetworkConfig {
+        NetworkConfig {
+            virtual_gateway: 1,
+            virtual_netmask: u32::MAX << 8,
+            address_range: (2, 254),
+            max_devices: 253,
+            require_handshake: true,
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_token_with_no_default() {
+        let provider = StaticNetworkConfigProvider::new();
+        provider.insert("known".to_string(), sample_config());
+
+        assert!(provider.resolve("known").is_some());
+        assert!(provider.resolve("unknown").is_none());
+    }
+
+    #[test]
+    fn with_default_falls_back_for_any_token() {
+        let provider = StaticNetworkConfigProvider::with_default(sample_config());
+
+        assert!(provider.resolve("anything").is_some());
+    }
+}
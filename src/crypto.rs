@@ -0,0 +1,269 @@
+//! Per-session transport encryption for the UDP/TCP control, service and
+//! turn-forwarded traffic.
+//!
+//! Modeled on the RLPx handshake: the server holds a long-lived static x25519
+//! keypair, each client presents a fresh ephemeral public key and nonce at
+//! registration, and the two sides derive a send/receive key pair with
+//! HKDF-SHA256 over the ECDH shared secret and both nonces. Payloads are then
+//! sealed with ChaCha20-Poly1305 using a per-packet counter nonce.
+//!
+//! Keys are only ever negotiated client<->server, never peer<->peer. That
+//! means turn-forwarded traffic (see `service::udp_service`'s `Ipv4Turn` /
+//! `OtherTurn` handling) has to be decrypted under the sender's session key
+//! and re-encrypted under each recipient's before it leaves the server — the
+//! server sees plaintext user payloads on every relay. That's a deliberate,
+//! and real, trust-model trade-off of adding transport encryption at all,
+//! not an oversight.
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::error::*;
+
+/// Width, in packet counters, of the sliding replay window tracked per
+/// session; one bit per counter in the 64-bit `recv_window` bitmap.
+const REPLAY_WINDOW: u64 = 64;
+
+/// Long-lived server identity used in the ECDH handshake.
+pub struct ServerIdentity {
+    static_secret: StaticSecret,
+    static_public: PublicKey,
+}
+
+impl ServerIdentity {
+    pub fn generate() -> Self {
+        let static_secret = StaticSecret::new(OsRng);
+        let static_public = PublicKey::from(&static_secret);
+        Self { static_secret, static_public }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.static_public.to_bytes()
+    }
+
+    /// Run the server side of the handshake against a client's ephemeral
+    /// public key and nonce, returning the server's ephemeral public key,
+    /// nonce, and the derived session crypto.
+    pub fn handshake(&self, client_ephemeral_public: &[u8], client_nonce: &[u8]) -> Result<(Vec<u8>, Vec<u8>, SessionCrypto)> {
+        if client_ephemeral_public.len() != 32 {
+            return Err(Error::Other("invalid ephemeral public key".into()));
+        }
+        let mut client_key_bytes = [0u8; 32];
+        client_key_bytes.copy_from_slice(client_ephemeral_public);
+        let client_ephemeral = PublicKey::from(client_key_bytes);
+
+        let server_ephemeral_secret = EphemeralSecret::new(OsRng);
+        let server_ephemeral_public = PublicKey::from(&server_ephemeral_secret);
+
+        let mut server_nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut server_nonce);
+
+        let shared_secret = server_ephemeral_secret.diffie_hellman(&client_ephemeral);
+        let crypto = SessionCrypto::derive(shared_secret.as_bytes(), client_nonce, &server_nonce)?;
+
+        Ok((server_ephemeral_public.to_bytes().to_vec(), server_nonce.to_vec(), crypto))
+    }
+}
+
+/// Symmetric keys and replay state for one client session, derived once at
+/// registration and cached alongside the session's `Context`.
+#[derive(Clone)]
+pub struct SessionCrypto {
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    send_counter: u64,
+    recv_highest: u64,
+    recv_window: u64,
+}
+
+impl std::fmt::Debug for SessionCrypto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCrypto").field("send_counter", &self.send_counter).finish()
+    }
+}
+
+impl SessionCrypto {
+    fn derive(shared_secret: &[u8], client_nonce: &[u8], server_nonce: &[u8]) -> Result<Self> {
+        let mut salt = Vec::with_capacity(client_nonce.len() + server_nonce.len());
+        salt.extend_from_slice(client_nonce);
+        salt.extend_from_slice(server_nonce);
+        let hk = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+
+        let mut okm = [0u8; 64];
+        hk.expand(b"switch-server session keys", &mut okm)
+            .map_err(|_| Error::Other("hkdf expand failed".into()))?;
+
+        let mut to_client = [0u8; 32];
+        let mut to_server = [0u8; 32];
+        to_client.copy_from_slice(&okm[..32]);
+        to_server.copy_from_slice(&okm[32..]);
+
+        // The server sends with `to_client` and receives with `to_server`;
+        // callers on the client side simply swap the two.
+        Ok(Self {
+            send_key: to_client,
+            recv_key: to_server,
+            send_counter: 0,
+            recv_highest: 0,
+            recv_window: 0,
+        })
+    }
+
+    fn cipher(key: &[u8; 32]) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(key))
+    }
+
+    fn nonce_from_counter(counter: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        *Nonce::from_slice(&nonce)
+    }
+
+    /// Seal `plaintext` under the next send counter, authenticating `aad`
+    /// (typically the packet header). Returns `counter || ciphertext||tag`.
+    pub fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+        let cipher = Self::cipher(&self.send_key);
+        let nonce = Self::nonce_from_counter(counter);
+        let ciphertext = cipher
+            .encrypt(&nonce, chacha20poly1305::aead::Payload { msg: plaintext, aad })
+            .map_err(|_| Error::Other("aead seal failed".into()))?;
+        let mut out = Vec::with_capacity(8 + ciphertext.len());
+        out.extend_from_slice(&counter.to_be_bytes());
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Verify and decrypt a frame produced by `seal`, rejecting replays via a
+    /// sliding window over the last [`REPLAY_WINDOW`] counters.
+    pub fn open(&mut self, aad: &[u8], frame: &[u8]) -> Result<Vec<u8>> {
+        if frame.len() < 8 {
+            return Err(Error::Other("frame too short".into()));
+        }
+        let mut counter_bytes = [0u8; 8];
+        counter_bytes.copy_from_slice(&frame[..8]);
+        let counter = u64::from_be_bytes(counter_bytes);
+        self.check_replay(counter)?;
+
+        let cipher = Self::cipher(&self.recv_key);
+        let nonce = Self::nonce_from_counter(counter);
+        let plaintext = cipher
+            .decrypt(&nonce, chacha20poly1305::aead::Payload { msg: &frame[8..], aad })
+            .map_err(|_| Error::Other("aead open failed".into()))?;
+
+        self.accept_replay(counter);
+        Ok(plaintext)
+    }
+
+    fn check_replay(&self, counter: u64) -> Result<()> {
+        if counter + REPLAY_WINDOW <= self.recv_highest {
+            return Err(Error::Other("replayed or stale packet".into()));
+        }
+        if counter <= self.recv_highest {
+            let shift = self.recv_highest - counter;
+            if shift < 64 && self.recv_window & (1 << shift) != 0 {
+                return Err(Error::Other("replayed packet".into()));
+            }
+        }
+        Ok(())
+    }
+
+    fn accept_replay(&mut self, counter: u64) {
+        if counter > self.recv_highest {
+            let shift = counter - self.recv_highest;
+            self.recv_window = if shift >= 64 { 0 } else { self.recv_window << shift };
+            self.recv_highest = counter;
+            // Bit 0 always tracks `recv_highest` itself, so replaying the
+            // packet we just accepted as the new high-water mark is caught
+            // by check_replay's `shift == 0` case instead of sailing through.
+            self.recv_window |= 1;
+        } else {
+            let shift = self.recv_highest - counter;
+            if shift < 64 {
+                self.recv_window |= 1 << shift;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `SessionCrypto::derive` always assigns `send_key = to_client` and
+    /// `recv_key = to_server`, since it's only ever called from the server
+    /// side of the handshake. Build a complementary client-side pair by hand
+    /// so seal() on one side lines up with open() on the other.
+    fn paired_session_crypto() -> (SessionCrypto, SessionCrypto) {
+        let server_secret = StaticSecret::new(OsRng);
+        let server_public = PublicKey::from(&server_secret);
+        let client_secret = EphemeralSecret::new(OsRng);
+        let client_public = PublicKey::from(&client_secret);
+
+        let client_nonce = [1u8; 32];
+        let server_nonce = [2u8; 32];
+
+        let shared_secret = client_secret.diffie_hellman(&server_public);
+        let server = SessionCrypto::derive(shared_secret.as_bytes(), &client_nonce, &server_nonce).unwrap();
+
+        let shared_secret = server_secret.diffie_hellman(&client_public);
+        let mut client = SessionCrypto::derive(shared_secret.as_bytes(), &client_nonce, &server_nonce).unwrap();
+        std::mem::swap(&mut client.send_key, &mut client.recv_key);
+
+        (server, client)
+    }
+
+    #[test]
+    fn round_trips_through_seal_and_open() {
+        let (mut server, mut client) = paired_session_crypto();
+
+        let frame = server.seal(b"hdr", b"hello").unwrap();
+        let plain = client.open(b"hdr", &frame).unwrap();
+        assert_eq!(plain, b"hello");
+    }
+
+    #[test]
+    fn rejects_replay_of_the_most_recently_accepted_frame() {
+        let (mut server, mut client) = paired_session_crypto();
+
+        let frame = server.seal(b"hdr", b"hello").unwrap();
+        client.open(b"hdr", &frame).unwrap();
+
+        // Replaying the exact same frame again must be rejected, not just a
+        // replay of some older, already-superseded counter.
+        assert!(client.open(b"hdr", &frame).is_err());
+    }
+
+    #[test]
+    fn accepts_out_of_order_packets_within_the_window() {
+        let (mut server, mut client) = paired_session_crypto();
+
+        let frame0 = server.seal(b"hdr", b"first").unwrap();
+        let frame1 = server.seal(b"hdr", b"second").unwrap();
+
+        client.open(b"hdr", &frame1).unwrap();
+        // An older, reordered-but-not-yet-seen packet should still pass.
+        client.open(b"hdr", &frame0).unwrap();
+        // But replaying it again must not.
+        assert!(client.open(b"hdr", &frame0).is_err());
+    }
+
+    #[test]
+    fn rejects_packet_older_than_the_replay_window() {
+        let (mut server, mut client) = paired_session_crypto();
+
+        let stale = server.seal(b"hdr", b"stale").unwrap();
+        for _ in 0..REPLAY_WINDOW {
+            let frame = server.seal(b"hdr", b"filler").unwrap();
+            client.open(b"hdr", &frame).unwrap();
+        }
+
+        assert!(client.open(b"hdr", &stale).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Per-network virtual IP allocator.
+//!
+//! Previously the server rebuilt a `HashSet` of every in-use address and
+//! linearly scanned the configured range on each registration. `IpPool`
+//! instead keeps the free addresses in a `BTreeSet`, so allocation takes the
+//! smallest free address in O(log n) and reclamation (driven by the moka
+//! eviction listeners) puts an address straight back in the pool in O(log n)
+//! too, instead of waiting for the next full rescan.
+
+use std::collections::BTreeSet;
+
+#[derive(Clone, Debug)]
+pub struct IpPool {
+    free: BTreeSet<u32>,
+}
+
+impl IpPool {
+    /// Build a pool holding every address in the inclusive range
+    /// `[start, end]` as free.
+    pub fn new(start: u32, end: u32) -> Self {
+        Self { free: (start..=end).collect() }
+    }
+
+    /// Take the smallest free address, or `None` if the pool is exhausted.
+    pub fn allocate(&mut self) -> Option<u32> {
+        let ip = *self.free.iter().next()?;
+        self.free.remove(&ip);
+        Some(ip)
+    }
+
+    /// Return an address to the pool so it can be handed out again.
+    pub fn release(&mut self, ip: u32) {
+        self.free.insert(ip);
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_in_order_and_exhausts() {
+        let mut pool = IpPool::new(1, 3);
+        assert_eq!(pool.allocate(), Some(1));
+        assert_eq!(pool.allocate(), Some(2));
+        assert_eq!(pool.allocate(), Some(3));
+        assert_eq!(pool.allocate(), None);
+    }
+
+    #[test]
+    fn reuses_address_after_release() {
+        let mut pool = IpPool::new(1, 2);
+        let a = pool.allocate().unwrap();
+        let _b = pool.allocate().unwrap();
+        assert_eq!(pool.allocate(), None);
+        pool.release(a);
+        assert_eq!(pool.allocate(), Some(a));
+    }
+
+    #[test]
+    fn full_subnet_round_trip() {
+        let start = u32::from_be_bytes([10, 13, 0, 2]);
+        let end = u32::from_be_bytes([10, 13, 0, 254]);
+        let mut pool = IpPool::new(start, end);
+        assert_eq!(pool.available(), (end - start + 1) as usize);
+
+        let mut allocated = Vec::new();
+        while let Some(ip) = pool.allocate() {
+            allocated.push(ip);
+        }
+        assert_eq!(allocated.len(), (end - start + 1) as usize);
+        assert_eq!(pool.allocate(), None);
+
+        for ip in allocated {
+            pool.release(ip);
+        }
+        assert_eq!(pool.available(), (end - start + 1) as usize);
+    }
+
+    #[test]
+    fn wraparound_reuses_lowest_freed_address_first() {
+        let mut pool = IpPool::new(1, 4);
+        let a = pool.allocate().unwrap();
+        let b = pool.allocate().unwrap();
+        let _c = pool.allocate().unwrap();
+        pool.release(b);
+        pool.release(a);
+        // releasing out of order still hands back the lowest address first
+        assert_eq!(pool.allocate(), Some(a));
+        assert_eq!(pool.allocate(), Some(b));
+    }
+}
@@ -0,0 +1,73 @@
+//! TCP fallback for clients on networks that block or heavily NAT UDP.
+//!
+//! Each `NetPacket` is length-prefixed (4-byte big-endian length + payload)
+//! over the stream. Registration, session tracking, ping/device-list sync
+//! and turn forwarding all go through the same `handle()`/`handle_()` logic
+//! as the UDP path, via the shared [`PeerChannel`] abstraction.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::error::*;
+use crate::service::transport::PeerChannel;
+use crate::service::udp_service::handle;
+
+const MAX_FRAME_LEN: u32 = 65536;
+const WRITE_QUEUE_SIZE: usize = 256;
+
+pub async fn handle_loop(listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, addr).await {
+                log::error!("tcp connection {:?} closed: {:?}", addr, e)
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, addr: std::net::SocketAddr) -> Result<()> {
+    let (mut read_half, mut write_half) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(WRITE_QUEUE_SIZE);
+
+    tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if let Err(e) = write_frame(&mut write_half, &frame).await {
+                log::error!("tcp write to {:?} failed: {:?}", addr, e);
+                break;
+            }
+        }
+    });
+
+    let channel = PeerChannel::Tcp(addr, tx);
+    loop {
+        let buf = match read_frame(&mut read_half).await? {
+            Some(buf) => buf,
+            None => return Ok(()),
+        };
+        if let Err(e) = handle(&channel, &buf).await {
+            log::error!("{:?}", e)
+        }
+    }
+}
+
+async fn read_frame(stream: &mut (impl AsyncReadExt + Unpin)) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    if stream.read_exact(&mut len_buf).await.is_err() {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(Error::Other(format!("invalid tcp frame length {}", len)));
+    }
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+async fn write_frame(stream: &mut (impl AsyncWriteExt + Unpin), buf: &[u8]) -> Result<()> {
+    stream.write_all(&(buf.len() as u32).to_be_bytes()).await?;
+    stream.write_all(buf).await?;
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! Abstraction over "how to reach this peer", so the registration/session
+//! and turn-forwarding logic in `udp_service` doesn't need to know whether a
+//! client showed up over UDP or over the TCP fallback channel.
+
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use std::sync::Arc;
+
+use crate::error::*;
+
+/// One `NetPacket` buffer queued for a client's TCP write task, which adds
+/// the length prefix when it writes the buffer to the stream.
+pub type TcpFrame = Vec<u8>;
+
+/// One registered peer's send side: either a shared UDP socket plus the
+/// address to send to, or a channel into that connection's dedicated TCP
+/// writer task.
+#[derive(Clone, Debug)]
+pub enum PeerChannel {
+    Udp(Arc<UdpSocket>, SocketAddr),
+    Tcp(SocketAddr, mpsc::Sender<TcpFrame>),
+}
+
+impl PeerChannel {
+    pub fn peer_addr(&self) -> SocketAddr {
+        match self {
+            PeerChannel::Udp(_, addr) => *addr,
+            PeerChannel::Tcp(addr, _) => *addr,
+        }
+    }
+
+    /// Send one already-framed `NetPacket` to this peer, writing it out on
+    /// whatever transport the peer registered on.
+    pub async fn send(&self, buf: &[u8]) -> Result<()> {
+        match self {
+            PeerChannel::Udp(udp, addr) => {
+                udp.send_to(buf, *addr).await?;
+            }
+            PeerChannel::Tcp(_, tx) => {
+                if tx.send(buf.to_vec()).await.is_err() {
+                    return Err(Error::Other("tcp peer disconnected".into()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
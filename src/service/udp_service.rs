@@ -1,5 +1,5 @@
-use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -7,13 +7,44 @@ use chrono::Local;
 use moka::sync::Cache;
 use parking_lot::Mutex;
 use protobuf::Message;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
 
+use crate::config::{NetworkConfig, NetworkConfigProvider, StaticNetworkConfigProvider};
+use crate::crypto::{ServerIdentity, SessionCrypto};
 use crate::error::*;
 use crate::proto::message;
 use crate::proto::message::{DeviceList, RegistrationRequest, RegistrationResponse};
 use crate::protocol::{control_packet, error_packet, NetPacket, Protocol, service_packet, Version};
 use crate::protocol::control_packet::PingPacket;
 use crate::protocol::turn_packet::TurnPacket;
+use crate::service::ip_pool::IpPool;
+use crate::service::transport::PeerChannel;
+
+lazy_static::lazy_static! {
+    // 服务端的静态密钥，用于和客户端的临时密钥做ECDH握手
+    static ref SERVER_IDENTITY: ServerIdentity = ServerIdentity::generate();
+    // token -> 网络配置，显式注册已知 token；未注册的 token 一律拒绝，
+    // 不能再像旧版那样把任何 token 都当成同一个网段放行。后续可换成数据库实现
+    static ref NETWORK_CONFIG_PROVIDER: Box<dyn NetworkConfigProvider> = {
+        let provider = StaticNetworkConfigProvider::new();
+        provider.insert(
+            "default".to_string(),
+            NetworkConfig {
+                virtual_gateway: u32::from_be_bytes([10, 13, 0, 1]),
+                virtual_netmask: u32::from_be_bytes([255, 255, 255, 0]),
+                address_range: (
+                    u32::from_be_bytes([10, 13, 0, 2]),
+                    u32::from_be_bytes([10, 13, 0, 254]),
+                ),
+                max_devices: 127,
+                // 默认 token 已完成灰度，要求握手；逐 token 放开用 provider.insert() 接入旧客户端
+                require_handshake: true,
+            },
+        );
+        Box::new(provider)
+    };
+}
 
 lazy_static::lazy_static! {
      static ref MAC_ADDRESS_SESSION:Cache<(String,String),()> = Cache::builder()
@@ -24,7 +55,9 @@ lazy_static::lazy_static! {
             log::info!("eviction {:?}", k);
             if let Some(v) = VIRTUAL_NETWORK.get(&k.0){
                 let mut lock = v.lock();
-                lock.virtual_ip_map.remove(&k.1);
+                if let Some(device_info) = lock.virtual_ip_map.remove(&k.1) {
+                    lock.allocator.release(device_info.ip);
+                }
                 lock.epoch+=1;
             }
          }).build();
@@ -48,8 +81,8 @@ lazy_static::lazy_static! {
                 lock.epoch+=1;
             }
          }).build();
-    // (token,ip) ->地址
-    static ref DEVICE_ADDRESS:Cache<(String,u32), SocketAddr> = Cache::builder()
+    // (token,ip) -> 该设备当前注册所在的传输通道（UDP地址或TCP连接句柄）
+    static ref DEVICE_ADDRESS:Cache<(String,u32), PeerChannel> = Cache::builder()
         .time_to_idle(Duration::from_secs(2 * 61)).build();
     static ref VIRTUAL_NETWORK:Cache<String, Arc<Mutex<VirtualNetwork>>> = Cache::builder()
         .time_to_idle(Duration::from_secs(60*60*24*7)).build();
@@ -60,11 +93,21 @@ struct Context {
     virtual_ip: u32,
     id: i64,
     mac_address: String,
+    // 该 token 尚未灰度到握手特性时为 None，此时会话按明文处理。用 Arc<Mutex<_>>
+    // 包起来，是因为中继转发（打洞、turn 转发）会从发起方所在的 worker 去给
+    // 对端的会话做 seal，而对端自己的 worker 也可能同时在用同一把密钥加密它自己
+    // 的流量；seal() 内部会推进 send_counter，如果两边各自拿到 Context 的独立
+    // 拷贝去改再各自写回缓存，就可能算出相同的 counter，对同一个 (key, nonce)
+    // 加密两份不同的明文，直接击穿 AEAD。共享同一个 Mutex<SessionCrypto> 能保证
+    // 无论哪个 worker 调用 seal/open，计数器的推进都是互斥的。
+    crypto: Option<Arc<Mutex<SessionCrypto>>>,
 }
 
 #[derive(Clone, Debug)]
 struct VirtualNetwork {
     epoch: u32,
+    config: NetworkConfig,
+    allocator: IpPool,
     // mac_address -> DeviceInfo
     virtual_ip_map: HashMap<String, DeviceInfo>,
 }
@@ -75,6 +118,10 @@ struct DeviceInfo {
     ip: u32,
     name: String,
     status: PeerDeviceStatus,
+    connection_state: ConnectionState,
+    // 最近一次服务端让它去打洞的目标地址，PunchAck 必须match这个值才采信，
+    // 防止客户端随意上报一个从未被要求打洞的地址
+    pending_punch_target: Option<u32>,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -101,20 +148,100 @@ impl From<u8> for PeerDeviceStatus {
     }
 }
 
-pub fn handle_loop(udp: UdpSocket) -> Result<()> {
+/// Whether a device's turn traffic currently goes straight to its peers or
+/// still has to be relayed through this server, as last observed after a
+/// hole-punch rendezvous.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    Relayed,
+    Direct,
+}
+
+impl Into<u8> for ConnectionState {
+    fn into(self) -> u8 {
+        match self {
+            ConnectionState::Relayed => 0,
+            ConnectionState::Direct => 1,
+        }
+    }
+}
+
+impl From<u8> for ConnectionState {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ConnectionState::Direct,
+            _ => ConnectionState::Relayed,
+        }
+    }
+}
+
+/// Datagrams for a single client always land on the same worker, so state
+/// tied to one `SocketAddr` (registration, replay windows) is only ever
+/// touched from one task. A slow client only holds up the clients whose
+/// addresses hash to the same worker.
+const WORKER_COUNT: usize = 8;
+const WORKER_QUEUE_SIZE: usize = 1024;
+
+fn worker_index(addr: &SocketAddr) -> usize {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    addr.hash(&mut hasher);
+    (hasher.finish() as usize) % WORKER_COUNT
+}
+
+/// Seal `plain` under `crypto`'s session key, or pass it through unchanged
+/// for sessions whose token hasn't been rolled onto the handshake yet.
+///
+/// Takes `&Option<Arc<Mutex<_>>>` rather than `&mut SessionCrypto`: the
+/// underlying mutex is shared with every clone of the owning `Context`
+/// (including ones a relay path pulled from `SESSION` for a *different*
+/// client's worker), so the short-lived lock taken here is what keeps
+/// concurrent seals from racing on the same `send_counter`.
+fn seal_payload(crypto: &Option<Arc<Mutex<SessionCrypto>>>, header: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+    match crypto {
+        Some(crypto) => crypto.lock().seal(header, plain),
+        None => Ok(plain.to_vec()),
+    }
+}
+
+/// Inverse of [`seal_payload`]: verifies and opens an AEAD frame when the
+/// session has a derived key, otherwise treats the frame as already plain.
+fn open_payload(crypto: &Option<Arc<Mutex<SessionCrypto>>>, header: &[u8], frame: &[u8]) -> Result<Vec<u8>> {
+    match crypto {
+        Some(crypto) => crypto.lock().open(header, frame),
+        None => Ok(frame.to_vec()),
+    }
+}
+
+pub async fn handle_loop(udp: UdpSocket) -> Result<()> {
+    let udp = Arc::new(udp);
+    let mut senders = Vec::with_capacity(WORKER_COUNT);
+    for _ in 0..WORKER_COUNT {
+        let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(WORKER_QUEUE_SIZE);
+        let udp = udp.clone();
+        tokio::spawn(async move {
+            while let Some((buf, addr)) = rx.recv().await {
+                let channel = PeerChannel::Udp(udp.clone(), addr);
+                if let Err(e) = handle(&channel, &buf).await {
+                    log::error!("{:?}", e)
+                }
+            }
+        });
+        senders.push(tx);
+    }
+
     let mut buf = [0u8; 65536];
     loop {
-        let (len, addr) = udp.recv_from(&mut buf)?;
-        match handle(&udp, &buf[..len], addr) {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("{:?}", e)
-            }
+        let (len, addr) = udp.recv_from(&mut buf).await?;
+        let sender = &senders[worker_index(&addr)];
+        if sender.try_send((buf[..len].to_vec(), addr)).is_err() {
+            log::error!("worker queue full, dropping packet from {:?}", addr);
         }
     }
 }
 
-fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
+pub(crate) async fn handle(channel: &PeerChannel, buf: &[u8]) -> Result<()> {
+    let addr = channel.peer_addr();
     let net_packet = NetPacket::new(buf)?;
     if net_packet.protocol() == Protocol::Service
         && net_packet.transport_protocol()
@@ -124,7 +251,43 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
     {
         let request = RegistrationRequest::parse_from_bytes(net_packet.payload())?;
         log::info!("register:{:?}",request);
+        let config = match NETWORK_CONFIG_PROVIDER.resolve(&request.token) {
+            Some(config) => config,
+            None => {
+                log::error!("unauthorized token:{:?}", request);
+                let mut net_packet = NetPacket::new([0u8; 4])?;
+                net_packet.set_version(Version::V1);
+                net_packet.set_protocol(Protocol::Error);
+                net_packet.set_transport_protocol(error_packet::Protocol::Unauthorized.into());
+                net_packet.set_ttl(255);
+                channel.send(net_packet.buffer()).await?;
+                return Ok(());
+            }
+        };
+        let has_handshake =
+            request.client_ephemeral_public_key.len() == 32 && request.client_nonce.len() == 32;
+        if !has_handshake && config.require_handshake {
+            // 该 token 已经完成灰度，要求握手；未带握手参数的客户端拒绝接入
+            log::error!("missing handshake parameters:{:?}", request);
+            let mut net_packet = NetPacket::new([0u8; 4])?;
+            net_packet.set_version(Version::V1);
+            net_packet.set_protocol(Protocol::Error);
+            net_packet.set_transport_protocol(error_packet::Protocol::Disconnect.into());
+            net_packet.set_ttl(255);
+            channel.send(net_packet.buffer()).await?;
+            return Ok(());
+        }
         let mut response = RegistrationResponse::new();
+        // 未灰度到该 token 的客户端允许跳过握手，此时会话不加密，兼容旧版本客户端
+        let crypto = if has_handshake {
+            let (server_ephemeral_public_key, server_nonce, crypto) = SERVER_IDENTITY
+                .handshake(&request.client_ephemeral_public_key, &request.client_nonce)?;
+            response.server_ephemeral_public_key = server_ephemeral_public_key;
+            response.server_nonce = server_nonce;
+            Some(Arc::new(Mutex::new(crypto)))
+        } else {
+            None
+        };
         match addr.ip() {
             IpAddr::V4(ipv4) => {
                 response.public_ip = ipv4.into();
@@ -135,12 +298,14 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
                 return Ok(());
             }
         }
-        //todo 暂时写死地址 考虑验证token,比如从数据库根据token读出网关
-        response.virtual_netmask = u32::from_be_bytes([255, 255, 255, 0]);
-        response.virtual_gateway = u32::from_be_bytes([10, 13, 0, 1]);
+        response.virtual_netmask = config.virtual_netmask;
+        response.virtual_gateway = config.virtual_gateway;
         if let Some(v) = VIRTUAL_NETWORK.optionally_get_with(request.token.clone(), || {
+            let (range_start, range_end) = config.address_range;
             Some(Arc::new(parking_lot::const_mutex(VirtualNetwork {
                 epoch: 0,
+                config: config.clone(),
+                allocator: IpPool::new(range_start, range_end),
                 virtual_ip_map: HashMap::new(),
             })))
         }) {
@@ -155,29 +320,24 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
                     (Local::now().timestamp_millis(), 0)
                 };
             if virtual_ip == 0 {
-                //获取一个未使用的ip
-                let set: HashSet<u32> = lock
-                    .virtual_ip_map
-                    .iter()
-                    .map(|(_, device_info)| device_info.ip)
-                    .collect();
-                for ip in response.virtual_gateway + 1..response.virtual_gateway + 128 {
-                    if !set.contains(&ip) {
-                        virtual_ip = ip;
-                        break;
+                let at_capacity = lock.virtual_ip_map.len() >= lock.config.max_devices;
+                let allocated = if at_capacity { None } else { lock.allocator.allocate() };
+                virtual_ip = match allocated {
+                    Some(ip) => ip,
+                    None => {
+                        log::error!("地址使用完:{:?}", request);
+                        // 发送失败响应前必须先释放锁，避免持锁跨越 .await
+                        drop(lock);
+                        let mut net_packet = NetPacket::new([0u8; 4])?;
+                        net_packet.set_version(Version::V1);
+                        net_packet.set_protocol(Protocol::Error);
+                        net_packet
+                            .set_transport_protocol(error_packet::Protocol::AddressExhausted.into());
+                        net_packet.set_ttl(255);
+                        channel.send(net_packet.buffer()).await?;
+                        return Ok(());
                     }
-                }
-                if virtual_ip == 0 {
-                    log::error!("地址使用完:{:?}", request);
-                    let mut net_packet = NetPacket::new([0u8; 4])?;
-                    net_packet.set_version(Version::V1);
-                    net_packet.set_protocol(Protocol::Error);
-                    net_packet
-                        .set_transport_protocol(error_packet::Protocol::AddressExhausted.into());
-                    net_packet.set_ttl(255);
-                    udp.send_to(net_packet.buffer(), addr)?;
-                    return Ok(());
-                }
+                };
                 lock.virtual_ip_map.insert(
                     request.mac_address.clone(),
                     DeviceInfo {
@@ -185,6 +345,8 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
                         name: request.name.clone(),
                         ip: virtual_ip,
                         status: PeerDeviceStatus::Online,
+                        connection_state: ConnectionState::Relayed,
+                        pending_punch_target: None,
                     },
                 );
             }
@@ -195,11 +357,13 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
                     dev.name = device_info.name.clone();
                     let status: u8 = device_info.status.into();
                     dev.device_status = status as u32;
+                    let connection_state: u8 = device_info.connection_state.into();
+                    dev.connection_state = connection_state as u32;
                     response.device_info_list.push(dev);
                 }
             }
             MAC_ADDRESS_SESSION.insert((request.token.clone(), request.mac_address.clone()), ());
-            DEVICE_ADDRESS.insert((request.token.clone(), virtual_ip), addr);
+            DEVICE_ADDRESS.insert((request.token.clone(), virtual_ip), channel.clone());
             drop(lock);
             response.virtual_ip = virtual_ip;
             SESSION.insert(
@@ -209,8 +373,11 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
                     virtual_ip,
                     id,
                     mac_address: request.mac_address.clone(),
+                    crypto,
                 },
             );
+        } else {
+            let _ = crypto;
         }
         let bytes = response.write_to_bytes()?;
         let send_buf = vec![0u8; 4 + bytes.len()];
@@ -220,9 +387,9 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
         net_packet.set_transport_protocol(service_packet::Protocol::RegistrationResponse.into());
         net_packet.set_ttl(255);
         net_packet.set_payload(&bytes);
-        udp.send_to(net_packet.buffer(), addr)?;
+        channel.send(net_packet.buffer()).await?;
         return Ok(());
-    } else if let Some(context) = SESSION.get(&addr) {
+    } else if let Some(mut context) = SESSION.get(&addr) {
         if DEVICE_ADDRESS
             .get(&(context.token.clone(), context.virtual_ip))
             .is_some()
@@ -231,7 +398,9 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
                 .get(&(context.token.clone(), context.mac_address.clone()))
                 .is_some()
             {
-                handle_(udp, addr, net_packet, context)?;
+                let handled = handle_(channel, net_packet, &mut context).await;
+                SESSION.insert(addr, context);
+                handled?;
                 return Ok(());
             }
         }
@@ -241,15 +410,67 @@ fn handle(udp: &UdpSocket, buf: &[u8], addr: SocketAddr) -> Result<()> {
     net_packet.set_protocol(Protocol::Error);
     net_packet.set_transport_protocol(error_packet::Protocol::Disconnect.into());
     net_packet.set_ttl(255);
-    udp.send_to(net_packet.buffer(), addr)?;
+    channel.send(net_packet.buffer()).await?;
     Ok(())
 }
 
-fn handle_(
-    udp: &UdpSocket,
-    addr: SocketAddr,
+/// Tell `to_channel` the observed public endpoint of `peer_ip`/`peer_addr`,
+/// so both sides of a hole-punch pair can send simultaneous probe packets.
+async fn send_punch_endpoint(
+    to_channel: &PeerChannel,
+    to_context: &mut Context,
+    peer_ip: u32,
+    peer_addr: SocketAddr,
+) -> Result<()> {
+    let mut endpoint = message::PunchEndpoint::new();
+    endpoint.virtual_ip = peer_ip;
+    match peer_addr.ip() {
+        IpAddr::V4(ipv4) => endpoint.public_ip = ipv4.into(),
+        IpAddr::V6(_) => return Ok(()),
+    }
+    endpoint.public_port = peer_addr.port() as u32;
+    let bytes = endpoint.write_to_bytes()?;
+
+    let header = [
+        Version::V1.into(),
+        Protocol::Control.into(),
+        control_packet::Protocol::PunchResponse.into(),
+        255,
+    ];
+    let sealed = seal_payload(&to_context.crypto, &header, &bytes)?;
+    let mut packet = NetPacket::new(vec![0u8; 4 + sealed.len()])?;
+    packet.set_version(Version::V1);
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::PunchResponse.into());
+    packet.set_ttl(255);
+    packet.set_payload(&sealed);
+    to_channel.send(packet.buffer()).await?;
+    Ok(())
+}
+
+/// Re-seal an already-opened turn payload under `peer_crypto` before handing
+/// it to the recipient. Each session derives its own key at handshake time,
+/// so the sender's sealed bytes can never be handed to the peer unmodified
+/// — the server has to terminate the sender's AEAD frame and re-wrap the
+/// plaintext for whichever session is receiving it.
+async fn forward_turn_payload(
+    peer: &PeerChannel,
+    header: &[u8],
+    plain: &[u8],
+    peer_crypto: &Option<Arc<Mutex<SessionCrypto>>>,
+) -> Result<()> {
+    let sealed = seal_payload(peer_crypto, header, plain)?;
+    let mut buf = Vec::with_capacity(header.len() + sealed.len());
+    buf.extend_from_slice(header);
+    buf.extend_from_slice(&sealed);
+    let packet = NetPacket::new(buf)?;
+    peer.send(packet.buffer()).await
+}
+
+async fn handle_(
+    channel: &PeerChannel,
     net_packet: NetPacket<&[u8]>,
-    context: Context,
+    context: &mut Context,
 ) -> Result<()> {
     match net_packet.protocol() {
         Protocol::Service => {
@@ -264,14 +485,25 @@ fn handle_(
         Protocol::Control => {
             match control_packet::Protocol::from(net_packet.transport_protocol()) {
                 control_packet::Protocol::Ping => {
-                    let mut pong = NetPacket::new([0u8; 4 + 8])?;
+                    // 服务端自行终结的控制包，必须先验证AEAD标签再信任其内容
+                    let ping_header = &net_packet.buffer()[..4];
+                    let plain = open_payload(&context.crypto, ping_header, net_packet.payload())?;
+
+                    let pong_header = [
+                        Version::V1.into(),
+                        Protocol::Control.into(),
+                        control_packet::Protocol::Pong.into(),
+                        255,
+                    ];
+                    let sealed_pong = seal_payload(&context.crypto, &pong_header, &plain[..8])?;
+                    let mut pong = NetPacket::new(vec![0u8; 4 + sealed_pong.len()])?;
                     pong.set_version(Version::V1);
                     pong.set_protocol(Protocol::Control);
                     pong.set_transport_protocol(control_packet::Protocol::Pong.into());
                     pong.set_ttl(255);
-                    pong.set_payload(&net_packet.payload()[..8]);
-                    udp.send_to(pong.buffer(), addr)?;
-                    let ping = PingPacket::new(net_packet.payload())?;
+                    pong.set_payload(&sealed_pong);
+                    channel.send(pong.buffer()).await?;
+                    let ping = PingPacket::new(&plain)?;
                     if let Some(v) = VIRTUAL_NETWORK.get(&context.token) {
                         //优先级较低，获取不到锁也问题不大
                         if let Some(lock) = v.try_lock() {
@@ -288,6 +520,8 @@ fn handle_(
                                         dev.name = device_info.name.clone();
                                         let status: u8 = device_info.status.into();
                                         dev.device_status = status as u32;
+                                        let connection_state: u8 = device_info.connection_state.into();
+                                        dev.connection_state = connection_state as u32;
                                         dev
                                     })
                                     .collect();
@@ -297,26 +531,98 @@ fn handle_(
                                 device_list.epoch = epoch;
                                 device_list.device_info_list = ips;
                                 let bytes = device_list.write_to_bytes()?;
+                                let header = [
+                                    Version::V1.into(),
+                                    Protocol::Service.into(),
+                                    service_packet::Protocol::UpdateDeviceList.into(),
+                                    255,
+                                ];
+                                let sealed = seal_payload(&context.crypto, &header, &bytes)?;
                                 let mut device_list_packet =
-                                    NetPacket::new(vec![0u8; 4 + bytes.len()])?;
+                                    NetPacket::new(vec![0u8; 4 + sealed.len()])?;
                                 device_list_packet.set_version(Version::V1);
                                 device_list_packet.set_protocol(Protocol::Service);
                                 device_list_packet.set_transport_protocol(
                                     service_packet::Protocol::UpdateDeviceList.into(),
                                 );
                                 device_list_packet.set_ttl(255);
-                                device_list_packet.set_payload(&bytes);
-                                udp.send_to(device_list_packet.buffer(), addr)?;
+                                device_list_packet.set_payload(&sealed);
+                                channel.send(device_list_packet.buffer()).await?;
                                 log::info!("device_list_packet {:?}",device_list_packet);
                             }
                         }
                     }
                 }
+                control_packet::Protocol::PunchRequest => {
+                    let header = &net_packet.buffer()[..4];
+                    let plain = open_payload(&context.crypto, header, net_packet.payload())?;
+                    let punch_request = message::PunchRequest::parse_from_bytes(&plain)?;
+                    let target_ip = punch_request.target_virtual_ip;
+                    let addr = channel.peer_addr();
+                    if let Some(peer_channel) = DEVICE_ADDRESS.get(&(context.token.clone(), target_ip)) {
+                        let peer_addr = peer_channel.peer_addr();
+                        if let Some(mut peer_context) = SESSION.get(&peer_addr) {
+                            send_punch_endpoint(channel, context, target_ip, peer_addr).await?;
+                            let from_ip = context.virtual_ip;
+                            let result = send_punch_endpoint(&peer_channel, &mut peer_context, from_ip, addr).await;
+                            SESSION.insert(peer_addr, peer_context);
+                            result?;
+                            // 连接状态留给双方各自成功打洞后上报的 PunchAck 去置 Direct，
+                            // 这里只是转发了双方的公网地址，不代表打洞一定会成功。记下双方
+                            // 本次被要求打洞的目标，PunchAck 上报时要跟这里对得上才采信
+                            if let Some(v) = VIRTUAL_NETWORK.get(&context.token) {
+                                let mut lock = v.lock();
+                                if let Some(dev) = lock.virtual_ip_map.get_mut(&context.mac_address) {
+                                    dev.pending_punch_target = Some(target_ip);
+                                }
+                                if let Some(dev) = lock.virtual_ip_map.values_mut().find(|d| d.ip == target_ip) {
+                                    dev.pending_punch_target = Some(context.virtual_ip);
+                                }
+                            }
+                        }
+                    }
+                }
+                control_packet::Protocol::PunchAck => {
+                    // 客户端确认已经和对端直连成功才上报，打洞失败（如对称NAT）时
+                    // 客户端不会发这个包，连接状态就保持默认的 Relayed
+                    let header = &net_packet.buffer()[..4];
+                    let plain = open_payload(&context.crypto, header, net_packet.payload())?;
+                    let punch_ack = message::PunchRequest::parse_from_bytes(&plain)?;
+                    let target_ip = punch_ack.target_virtual_ip;
+                    log::info!("punch ack: {:?} -> {}", context.mac_address, target_ip);
+                    if let Some(v) = VIRTUAL_NETWORK.get(&context.token) {
+                        let mut lock = v.lock();
+                        if let Some(dev) = lock.virtual_ip_map.get_mut(&context.mac_address) {
+                            // 只采信跟最近一次服务端下发的打洞目标匹配的确认，
+                            // 拒绝客户端伪造或过期的 ack
+                            if dev.pending_punch_target == Some(target_ip) {
+                                dev.connection_state = ConnectionState::Direct;
+                            } else {
+                                log::warn!(
+                                    "unexpected punch ack from {:?} for {}, pending={:?}",
+                                    context.mac_address, target_ip, dev.pending_punch_target
+                                );
+                            }
+                        }
+                    }
+                }
                 _ => {}
             }
         }
         Protocol::Ipv4Turn | Protocol::OtherTurn => {
-            let ipv4_turn_packet = TurnPacket::new(net_packet.payload())?;
+            // 每个会话都有各自派生的密钥，不能把发送方的密文原样转发给对端：
+            // 先用发送方会话密钥解出明文，转发前再按各接收方自己的会话密钥重新封装。
+            //
+            // NOTE(trust model, needs sign-off): 握手只建立了客户端<->服务端的
+            // 密钥，双方并没有做端到端的协商，所以中转转发这一步服务端必然要解密
+            // 出用户的明文流量再重新加密，而不是像握手引入之前那样原样转发密文。
+            // 也就是说服务端现在能看到所有经中转转发的对端流量内容，这是一次真实
+            // 的信任模型变化，不只是"给同样的转发逻辑加了层壳"。在有端到端协商
+            // 之前这大概是这套设计下唯一可行的做法，但这个权衡需要提出需求的人
+            // 明确确认，而不是当作普通 bug fix 悄悄合并。
+            let header = &net_packet.buffer()[..4];
+            let plain = open_payload(&context.crypto, header, net_packet.payload())?;
+            let ipv4_turn_packet = TurnPacket::new(&plain)?;
             let dest = ipv4_turn_packet.destination();
             //todo 暂时写死地址
             let broadcast = Ipv4Addr::from([10, 13, 0, 255]);
@@ -333,15 +639,27 @@ fn handle_(
                         drop(lock);
                         for ip in ips {
                             if let Some(peer) = DEVICE_ADDRESS.get(&(context.token.clone(), ip)) {
-                                udp.send_to(net_packet.buffer(), peer)?;
+                                let peer_addr = peer.peer_addr();
+                                if let Some(mut peer_context) = SESSION.get(&peer_addr) {
+                                    let sent =
+                                        forward_turn_payload(&peer, header, &plain, &peer_context.crypto)
+                                            .await;
+                                    SESSION.insert(peer_addr, peer_context);
+                                    if let Err(e) = sent {
+                                        log::error!("{:?}", e);
+                                    }
+                                }
                             }
                         }
                     }
                 }
-            } else if let Some(peer) =
-                DEVICE_ADDRESS.get(&(context.token, ipv4_turn_packet.destination().into()))
-            {
-                udp.send_to(net_packet.buffer(), peer)?;
+            } else if let Some(peer) = DEVICE_ADDRESS.get(&(context.token.clone(), dest.into())) {
+                let peer_addr = peer.peer_addr();
+                if let Some(mut peer_context) = SESSION.get(&peer_addr) {
+                    let result = forward_turn_payload(&peer, header, &plain, &peer_context.crypto).await;
+                    SESSION.insert(peer_addr, peer_context);
+                    result?;
+                }
             }
         }
         Protocol::UnKnow(_) => {}